@@ -0,0 +1,225 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use sha2::{Digest, Sha256};
+
+/// CqlFile represents a single `.cql` migration script discovered on disk. `path` is the file's
+/// location, [crate::MigrateOpts::cql_dir] joined with its file name; the history table keys it
+/// by file name alone (see [CqlFile::description]), so migration history stays stable across
+/// machines.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CqlFile {
+    pub path: PathBuf,
+}
+
+impl CqlFile {
+    /// description returns the file name, which is also the key recorded in the `migrated_cql`
+    /// history table.
+    pub fn description(&self) -> String {
+        self.path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default()
+    }
+
+    /// statements reads this file's contents and splits them into the individual CQL statements
+    /// to execute in order, via [split_statements].
+    pub fn statements(&self) -> Result<Vec<String>> {
+        let contents = fs::read_to_string(&self.path)?;
+        Ok(split_statements(&contents))
+    }
+
+    /// content_hash computes a hex-encoded SHA-256 digest of this file's statements, normalized
+    /// by [split_statements]: comments and whitespace between statements don't affect the hash,
+    /// but whitespace inside a statement (including re-indenting it, or the space left where a
+    /// comment inside it was dropped) does. Used to detect when a file already recorded as
+    /// applied has since been edited on disk.
+    pub fn content_hash(&self) -> Result<String> {
+        let normalized = self.statements()?.join("\n");
+        let mut hasher = Sha256::new();
+        hasher.update(normalized.as_bytes());
+        Ok(hex::encode(hasher.finalize()))
+    }
+}
+
+/// files_from_dir reads `dir` for `.cql` files, returning them in ascending file name order so
+/// migrations apply deterministically.
+pub fn files_from_dir(dir: &Path) -> Result<Vec<CqlFile>> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().map(|ext| ext == "cql").unwrap_or(false))
+        .collect();
+    paths.sort();
+    Ok(paths.into_iter().map(|path| CqlFile { path }).collect())
+}
+
+#[derive(Clone, Copy, Eq, PartialEq)]
+enum LexState {
+    Default,
+    SingleQuoted,
+    DoubleQuoted,
+    LineComment,
+    BlockComment,
+}
+
+/// split_statements scans `contents` character-by-character, tracking whether the cursor is
+/// inside a single-quoted string literal (handling `''` escapes), a double-quoted identifier, a
+/// `--`/`//` line comment, or a `/* */` block comment, and splits on `;` only when none of those
+/// apply. Comment text is dropped; statement text is trimmed, and empty statements are discarded,
+/// so semicolons or comment markers inside literals/comments never corrupt statement boundaries.
+fn split_statements(contents: &str) -> Vec<String> {
+    let chars: Vec<char> = contents.chars().collect();
+    let mut state = LexState::Default;
+    let mut statement = String::new();
+    let mut statements = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+
+        match state {
+            LexState::Default => match c {
+                '\'' => {
+                    state = LexState::SingleQuoted;
+                    statement.push(c);
+                }
+                '"' => {
+                    state = LexState::DoubleQuoted;
+                    statement.push(c);
+                }
+                '-' if next == Some('-') => {
+                    state = LexState::LineComment;
+                    i += 1;
+                }
+                '/' if next == Some('/') => {
+                    state = LexState::LineComment;
+                    i += 1;
+                }
+                '/' if next == Some('*') => {
+                    state = LexState::BlockComment;
+                    i += 1;
+                }
+                ';' => {
+                    let trimmed = statement.trim();
+                    if !trimmed.is_empty() {
+                        statements.push(trimmed.to_string());
+                    }
+                    statement.clear();
+                }
+                _ => statement.push(c),
+            },
+            LexState::SingleQuoted => match c {
+                '\'' if next == Some('\'') => {
+                    statement.push_str("''");
+                    i += 1;
+                }
+                '\'' => {
+                    state = LexState::Default;
+                    statement.push(c);
+                }
+                _ => statement.push(c),
+            },
+            LexState::DoubleQuoted => match c {
+                '"' => {
+                    state = LexState::Default;
+                    statement.push(c);
+                }
+                _ => statement.push(c),
+            },
+            LexState::LineComment => {
+                if c == '\n' {
+                    state = LexState::Default;
+                    statement.push(c);
+                }
+            }
+            LexState::BlockComment => {
+                if c == '*' && next == Some('/') {
+                    state = LexState::Default;
+                    statement.push(' ');
+                    i += 1;
+                }
+            }
+        }
+        i += 1;
+    }
+
+    let trimmed = statement.trim();
+    if !trimmed.is_empty() {
+        statements.push(trimmed.to_string());
+    }
+    statements
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_statements_multiple_statements() {
+        let contents = "CREATE TABLE a (id int PRIMARY KEY);\nINSERT INTO a (id) VALUES (1);";
+        assert_eq!(
+            split_statements(contents),
+            vec![
+                "CREATE TABLE a (id int PRIMARY KEY)",
+                "INSERT INTO a (id) VALUES (1)",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_split_statements_ignores_semicolon_in_string_literal() {
+        let contents = "INSERT INTO a (name) VALUES ('a;b');";
+        assert_eq!(
+            split_statements(contents),
+            vec!["INSERT INTO a (name) VALUES ('a;b')"]
+        );
+    }
+
+    #[test]
+    fn test_split_statements_handles_escaped_quote_in_string_literal() {
+        let contents = "INSERT INTO a (name) VALUES ('it''s; fine');";
+        assert_eq!(
+            split_statements(contents),
+            vec!["INSERT INTO a (name) VALUES ('it''s; fine')"]
+        );
+    }
+
+    #[test]
+    fn test_split_statements_strips_line_comments() {
+        let contents = "-- a comment with a ; in it\nCREATE TABLE a (id int PRIMARY KEY);\n// another ; comment\n";
+        assert_eq!(
+            split_statements(contents),
+            vec!["CREATE TABLE a (id int PRIMARY KEY)"]
+        );
+    }
+
+    #[test]
+    fn test_split_statements_strips_block_comments() {
+        let contents = "/* a ; block comment */ CREATE TABLE a (id int PRIMARY KEY);";
+        assert_eq!(
+            split_statements(contents),
+            vec!["CREATE TABLE a (id int PRIMARY KEY)"]
+        );
+    }
+
+    #[test]
+    fn test_split_statements_block_comment_does_not_join_adjacent_tokens() {
+        let contents = "CREATE TABLE foo (id/* pk */int PRIMARY KEY);";
+        assert_eq!(
+            split_statements(contents),
+            vec!["CREATE TABLE foo (id int PRIMARY KEY)"]
+        );
+    }
+
+    #[test]
+    fn test_split_statements_discards_empty_statements() {
+        let contents = ";;CREATE TABLE a (id int PRIMARY KEY);;";
+        assert_eq!(
+            split_statements(contents),
+            vec!["CREATE TABLE a (id int PRIMARY KEY)"]
+        );
+    }
+}
@@ -1,11 +1,15 @@
+use std::sync::Arc;
+use std::time::Duration;
 use std::{path::PathBuf, str};
 
 use anyhow::{anyhow, Result};
+use scylla::retry_policy::RetryPolicy;
 use scylla::Session;
 
 pub use crate::cql::CqlFile;
 use crate::keyspace::*;
-pub use crate::migrate::{MigrateError, MigrateErrorState};
+use crate::migrate::await_schema_agreement;
+pub use crate::migrate::{ConsistencyLevel, MigrateError, MigrateErrorState};
 use crate::queries::*;
 
 mod cql;
@@ -21,30 +25,133 @@ pub const KEYSPACE: &str = "cquill";
 
 pub const TABLE: &str = "migrated_cql";
 
+/// Default time to wait for cluster-wide schema agreement after each applied cql file before
+/// [MigrateError::SchemaAgreementTimeout] is returned.
+const DEFAULT_SCHEMA_AGREEMENT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Default consistency level for the applied-keyspace statements and the history-table writes.
+const DEFAULT_CONSISTENCY: ConsistencyLevel = ConsistencyLevel::LocalQuorum;
+
 pub struct MigrateOpts {
     pub cassandra_opts: Option<CassandraOpts>,
     pub cql_dir: PathBuf,
     pub apply_keyspace: String,
     pub history_keyspace: Option<KeyspaceOpts>,
     pub history_table: Option<String>,
+    /// How long to wait for all cluster nodes to agree on the schema version after each
+    /// DDL-bearing cql file is applied. Defaults to [DEFAULT_SCHEMA_AGREEMENT_TIMEOUT] when
+    /// `None`. The wait always runs, so on a multi-node cluster a subsequent migration does not
+    /// race a node that has not yet seen the previous DDL.
+    pub schema_agreement_timeout: Option<Duration>,
+    /// Consistency level for the applied-keyspace statements and the history-table writes.
+    /// Defaults to [ConsistencyLevel::LocalQuorum] when `None`.
+    pub consistency: Option<ConsistencyLevel>,
+    /// Retry policy for the same statements. The statements are not marked as idempotent on the
+    /// driver queries.
+    pub retry_policy: Option<Arc<dyn RetryPolicy>>,
 }
 
 #[derive(Default)]
 pub struct CassandraOpts {
-    pub cassandra_host: Option<String>,
+    /// Contact points used to discover the rest of the cluster. Unlike a single coordinator
+    /// address, this survives any one of them being down at startup. When empty, falls back to
+    /// the `CASSANDRA_NODE` env var (comma-separated) and then [NODE_ADDRESS].
+    pub contact_points: Vec<String>,
+    /// The local datacenter to prefer when load-balancing requests, so migrations against a
+    /// `NetworkTopologyStrategy` keyspace (see [keyspace::ReplicationFactor]) don't depend on
+    /// reaching a coordinator outside the operator's own DC.
+    pub local_datacenter: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub tls: Option<TlsOpts>,
 }
 
 impl CassandraOpts {
-    pub fn node_address(&self) -> String {
-        let node_address = match &self.cassandra_host {
-            None => std::env::var("CASSANDRA_NODE").unwrap_or(NODE_ADDRESS.to_string()),
-            Some(cassandra_host) => cassandra_host.clone(),
+    /// node_addresses resolves the contact points to register with the driver, each normalized
+    /// to include a port.
+    pub fn node_addresses(&self) -> Vec<String> {
+        let contact_points = if !self.contact_points.is_empty() {
+            self.contact_points.clone()
+        } else {
+            match std::env::var("CASSANDRA_NODE") {
+                Ok(node) => node.split(',').map(|s| s.trim().to_string()).collect(),
+                Err(_) => vec![NODE_ADDRESS.to_string()],
+            }
         };
-        if node_address.contains(':') {
-            node_address
+        contact_points
+            .into_iter()
+            .map(|contact_point| {
+                if contact_point.contains(':') {
+                    contact_point
+                } else {
+                    format!("{contact_point}:9042")
+                }
+            })
+            .collect()
+    }
+
+    /// credentials resolves the username/password to authenticate with, each field that is not
+    /// set falling back to its own env var, `CASSANDRA_USER` or `CASSANDRA_PASSWORD` (mirroring
+    /// [CassandraOpts::node_addresses]' `CASSANDRA_NODE` fallback). Returns `None` when no
+    /// username is available, since an anonymous connection needs no credentials.
+    fn credentials(&self) -> Option<(String, String)> {
+        let username = self
+            .username
+            .clone()
+            .or_else(|| std::env::var("CASSANDRA_USER").ok())?;
+        let password = self
+            .password
+            .clone()
+            .or_else(|| std::env::var("CASSANDRA_PASSWORD").ok())
+            .unwrap_or_default();
+        Some((username, password))
+    }
+}
+
+/// TlsOpts configures an encrypted connection to a cluster whose nodes terminate TLS, such as
+/// Scylla Cloud or a production Cassandra cluster fronted by a proxy.
+pub struct TlsOpts {
+    /// Path to a PEM-encoded CA certificate used to verify the node's certificate.
+    pub ca_cert_path: Option<PathBuf>,
+    /// Path to a PEM-encoded client certificate, for clusters requiring mutual TLS.
+    pub client_cert_path: Option<PathBuf>,
+    /// Path to the PEM-encoded private key matching [TlsOpts::client_cert_path].
+    pub client_key_path: Option<PathBuf>,
+    /// Whether to verify the node's certificate against [TlsOpts::ca_cert_path]. Defaults to
+    /// `true`; disabling this should only be done against trusted networks, e.g. local
+    /// development.
+    pub verify: bool,
+}
+
+impl Default for TlsOpts {
+    fn default() -> Self {
+        TlsOpts {
+            ca_cert_path: None,
+            client_cert_path: None,
+            client_key_path: None,
+            verify: true,
+        }
+    }
+}
+
+impl TlsOpts {
+    fn ssl_context(&self) -> Result<openssl::ssl::SslContext> {
+        let mut builder = openssl::ssl::SslContextBuilder::new(openssl::ssl::SslMethod::tls())?;
+        if let Some(ca_cert_path) = &self.ca_cert_path {
+            builder.set_ca_file(ca_cert_path)?;
+        }
+        if let (Some(cert_path), Some(key_path)) =
+            (&self.client_cert_path, &self.client_key_path)
+        {
+            builder.set_certificate_file(cert_path, openssl::ssl::SslFiletype::PEM)?;
+            builder.set_private_key_file(key_path, openssl::ssl::SslFiletype::PEM)?;
+        }
+        if self.verify {
+            builder.set_verify(openssl::ssl::SslVerifyMode::PEER);
         } else {
-            format!("{node_address}:9042")
+            builder.set_verify(openssl::ssl::SslVerifyMode::NONE);
         }
+        Ok(builder.build().into_context())
     }
 }
 
@@ -54,14 +161,19 @@ impl CassandraOpts {
 /// method result contains a vec of the cql script paths executed during this invocation.
 pub async fn migrate_cql(opts: MigrateOpts) -> Result<Vec<CqlFile>, MigrateError> {
     let cql_files = cql::files_from_dir(&opts.cql_dir)?;
-    let node_address = opts.cassandra_opts.unwrap_or_default().node_address();
-    let session = cql_session(node_address).await?;
+    let cassandra_opts = opts.cassandra_opts.unwrap_or_default();
+    let session = cql_session(&cassandra_opts).await?;
 
     let cquill_keyspace = opts
         .history_keyspace
         .unwrap_or_else(|| KeyspaceOpts::simple(String::from(KEYSPACE), 1));
     let history_table = opts.history_table.unwrap_or_else(|| String::from(TABLE));
+    let schema_agreement_timeout = opts
+        .schema_agreement_timeout
+        .unwrap_or(DEFAULT_SCHEMA_AGREEMENT_TIMEOUT);
+    let consistency = opts.consistency.unwrap_or(DEFAULT_CONSISTENCY);
     prepare_cquill_keyspace(&session, &cquill_keyspace, &history_table).await?;
+    await_schema_agreement(&session, None, schema_agreement_timeout).await?;
 
     migrate::perform(
         &session,
@@ -71,6 +183,9 @@ pub async fn migrate_cql(opts: MigrateOpts) -> Result<Vec<CqlFile>, MigrateError
             apply_keyspace: opts.apply_keyspace,
             history_keyspace: cquill_keyspace.name,
             history_table,
+            schema_agreement_timeout,
+            consistency,
+            retry_policy: opts.retry_policy,
         },
     )
     .await
@@ -91,18 +206,57 @@ async fn prepare_cquill_keyspace(
     };
     if create_table {
         migrated::table::create(session, &keyspace.name, table_name).await?;
+    } else {
+        // The table may have been created by a cquill version before the content_hash column
+        // existed; add it now so applied_files' SELECT of content_hash doesn't fail.
+        migrated::table::add_content_hash_column(session, &keyspace.name, table_name).await?;
     }
     Ok(())
 }
 
-async fn cql_session(node_address: String) -> Result<Session> {
-    let connecting = scylla::SessionBuilder::new()
-        .known_node(&node_address)
-        .build()
-        .await;
-    match connecting {
+async fn cql_session(cassandra_opts: &CassandraOpts) -> Result<Session> {
+    let node_addresses = cassandra_opts.node_addresses();
+    let mut builder = scylla::SessionBuilder::new();
+    for node_address in &node_addresses {
+        builder = builder.known_node(node_address);
+    }
+
+    if let Some(local_datacenter) = &cassandra_opts.local_datacenter {
+        let policy = scylla::transport::load_balancing::DefaultPolicy::builder()
+            .prefer_datacenter(local_datacenter.clone())
+            .build();
+        builder = builder.load_balancing_policy(policy);
+    }
+
+    if let Some((username, password)) = cassandra_opts.credentials() {
+        builder = builder.user(username, password);
+    }
+
+    if let Some(tls) = &cassandra_opts.tls {
+        builder = builder.ssl_context(Some(tls.ssl_context()?));
+    }
+
+    match builder.build().await {
         Ok(session) => Ok(session),
-        Err(_) => Err(anyhow!("could not connect to {}", &node_address)),
+        Err(err) => Err(classify_connection_error(&node_addresses.join(","), err)),
+    }
+}
+
+/// classify_connection_error turns a driver connection failure into a message that distinguishes
+/// an authentication failure, a TLS handshake failure, and a plain connectivity failure, since
+/// all three surface identically as "could not connect" otherwise.
+fn classify_connection_error(
+    node_address: &str,
+    err: scylla::transport::errors::NewSessionError,
+) -> anyhow::Error {
+    let message = err.to_string();
+    let lower = message.to_lowercase();
+    if lower.contains("auth") || lower.contains("credentials") {
+        anyhow!("authentication to {node_address} failed: {message}")
+    } else if lower.contains("ssl") || lower.contains("tls") || lower.contains("certificate") {
+        anyhow!("TLS handshake with {node_address} failed: {message}")
+    } else {
+        anyhow!("could not connect to {node_address}: {message}")
     }
 }
 
@@ -111,22 +265,30 @@ mod tests {
     use super::*;
 
     #[test]
-    fn test_cassandra_opts_provides_node_address() {
-        let without_host = CassandraOpts {
-            cassandra_host: None,
-        };
-        let with_host = CassandraOpts {
-            cassandra_host: Some("localhost".to_string()),
+    fn test_cassandra_opts_provides_node_addresses() {
+        let without_contact_points = CassandraOpts::default();
+        let with_contact_point = CassandraOpts {
+            contact_points: vec!["localhost".to_string()],
+            ..Default::default()
         };
         let with_port = CassandraOpts {
-            cassandra_host: Some("localhost:9043".to_string()),
+            contact_points: vec!["localhost:9043".to_string()],
+            ..Default::default()
         };
+        let with_multiple = CassandraOpts {
+            contact_points: vec!["node1".to_string(), "node2:9043".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            without_contact_points.node_addresses(),
+            vec![std::env::var("CASSANDRA_NODE").unwrap_or(NODE_ADDRESS.to_string())]
+        );
+        assert_eq!(with_contact_point.node_addresses(), vec!["localhost:9042"]);
+        assert_eq!(with_port.node_addresses(), vec!["localhost:9043"]);
         assert_eq!(
-            without_host.node_address(),
-            std::env::var("CASSANDRA_NODE").unwrap_or(NODE_ADDRESS.to_string())
+            with_multiple.node_addresses(),
+            vec!["node1:9042", "node2:9043"]
         );
-        assert_eq!(with_host.node_address(), "localhost:9042");
-        assert_eq!(with_port.node_address(), "localhost:9043");
     }
 
     #[tokio::test]
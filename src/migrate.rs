@@ -0,0 +1,514 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use scylla::retry_policy::RetryPolicy;
+use scylla::statement::Consistency;
+use scylla::Session;
+use thiserror::Error;
+
+use crate::cql::CqlFile;
+use crate::queries;
+
+/// ConsistencyLevel is the subset of the driver's consistency levels relevant to migrations:
+/// writes to `args.apply_keyspace` and to the `migrated_cql` history table are both executed at
+/// this level, so a transient coordinator hiccup on a multi-replica keyspace can be retried
+/// instead of leaving the history table out of sync with the applied schema.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ConsistencyLevel {
+    LocalQuorum,
+    Quorum,
+    All,
+}
+
+impl From<ConsistencyLevel> for Consistency {
+    fn from(level: ConsistencyLevel) -> Self {
+        match level {
+            ConsistencyLevel::LocalQuorum => Consistency::LocalQuorum,
+            ConsistencyLevel::Quorum => Consistency::Quorum,
+            ConsistencyLevel::All => Consistency::All,
+        }
+    }
+}
+
+/// MigrateArgs carries the resolved, non-optional arguments [crate::migrate_cql] hands off to
+/// [perform] once defaults from [crate::MigrateOpts] have been applied.
+pub struct MigrateArgs {
+    pub cql_dir: PathBuf,
+    pub apply_keyspace: String,
+    pub history_keyspace: String,
+    pub history_table: String,
+    /// How long to wait for cluster-wide schema agreement after each DDL-bearing cql file (and
+    /// after the cquill keyspace/table are prepared) before giving up with
+    /// [MigrateError::SchemaAgreementTimeout].
+    pub schema_agreement_timeout: Duration,
+    /// Consistency level applied to both the applied-keyspace statements and the history-table
+    /// writes.
+    pub consistency: ConsistencyLevel,
+    /// Retry policy applied to the same statements. The applied statements are whatever the cql
+    /// files hold, and none of the queries is marked as idempotent.
+    pub retry_policy: Option<Arc<dyn RetryPolicy>>,
+}
+
+/// MigrateErrorState describes how far a failed migration got, so operators can tell whether the
+/// `migrated_cql` history table is consistent with what was actually applied.
+#[derive(Debug, Eq, PartialEq)]
+pub enum MigrateErrorState {
+    /// No cql files were applied and recorded before the failure.
+    NoFilesApplied,
+    /// At least one cql file was applied and recorded in the history table before the failure
+    /// described by the accompanying [MigrateError]. `cql_file` is the last such file, in
+    /// applied order.
+    FilesApplied { cql_file: CqlFile },
+}
+
+impl MigrateErrorState {
+    fn after(last_applied: Option<&CqlFile>) -> Self {
+        match last_applied {
+            None => MigrateErrorState::NoFilesApplied,
+            Some(cql_file) => MigrateErrorState::FilesApplied {
+                cql_file: cql_file.clone(),
+            },
+        }
+    }
+}
+
+/// MigrateError is returned by [crate::migrate_cql] and [perform] when a migration could not be
+/// completed.
+#[derive(Error, Debug)]
+pub enum MigrateError {
+    #[error("{source}")]
+    Other {
+        #[from]
+        source: anyhow::Error,
+    },
+
+    #[error("failed reading cql file {path}: {source}")]
+    ReadCqlFile {
+        path: PathBuf,
+        source: anyhow::Error,
+        state: MigrateErrorState,
+    },
+
+    /// The cql file's statements failed to apply. The `migrated_cql` history table was not
+    /// touched for this file, so `state` names the last recorded file; any of this file's
+    /// statements that ran before the failing one stay applied.
+    #[error("failed applying cql file {cql_file:?}: {source}")]
+    ApplyCqlFile {
+        cql_file: CqlFile,
+        source: anyhow::Error,
+        state: MigrateErrorState,
+    },
+
+    /// The cql file's statements applied successfully, but recording it in the `migrated_cql`
+    /// history table failed. The schema has already changed for this file even though `state`
+    /// does not yet include it — re-running the migration will try to apply it again.
+    #[error("applied cql file {cql_file:?} but failed recording it as migrated: {source}")]
+    RecordHistoryWrite {
+        cql_file: CqlFile,
+        source: anyhow::Error,
+        state: MigrateErrorState,
+    },
+
+    #[error("cluster did not reach schema agreement within {timeout_secs}s after {cql_file:?}")]
+    SchemaAgreementTimeout {
+        cql_file: Option<CqlFile>,
+        timeout_secs: u64,
+    },
+
+    /// A cql file already recorded as applied has different content on disk than when it was
+    /// applied. Migrating on top of this would mean the deployed schema no longer matches what
+    /// the history table claims was run, so `perform` aborts before applying anything new.
+    #[error(
+        "cql file {cql_file:?} has changed since it was applied \
+         (recorded hash {recorded_hash}, current hash {current_hash})"
+    )]
+    ContentDrift {
+        cql_file: CqlFile,
+        recorded_hash: String,
+        current_hash: String,
+    },
+}
+
+/// perform applies each of `cql_files` not already recorded in the `migrated_cql` history table,
+/// in order, against `args.apply_keyspace`. Each file's statements are executed one by one, and
+/// the file's single history row is written only once all of them have applied; statements that
+/// applied before a failing one in the same file are not rolled back. The returned
+/// `Vec<CqlFile>` lists only the files applied during this invocation.
+///
+/// Before applying anything new, every already-applied file is re-hashed and compared against
+/// its recorded `content_hash`, aborting with [MigrateError::ContentDrift] on a mismatch. Rows
+/// recorded before the `content_hash` column existed (`None`) are backfilled from the current
+/// on-disk content instead, since there is no earlier hash to compare against.
+pub async fn perform(
+    session: &Session,
+    cql_files: &[CqlFile],
+    args: MigrateArgs,
+) -> Result<Vec<CqlFile>, MigrateError> {
+    let applied = queries::migrated::table::applied_files(
+        session,
+        &args.history_keyspace,
+        &args.history_table,
+    )
+    .await?;
+    let applied_by_name: std::collections::HashMap<String, Option<String>> = applied
+        .into_iter()
+        .map(|applied_file| (applied_file.cql_file, applied_file.content_hash))
+        .collect();
+
+    session
+        .use_keyspace(&args.apply_keyspace, false)
+        .await
+        .map_err(anyhow::Error::from)?;
+
+    let mut newly_applied = Vec::new();
+    for cql_file in cql_files {
+        let Some(recorded_hash) = applied_by_name.get(&cql_file.description()) else {
+            continue;
+        };
+
+        let current_hash = cql_file.content_hash().map_err(|source| MigrateError::ReadCqlFile {
+            path: cql_file.path.clone(),
+            source,
+            state: MigrateErrorState::after(None),
+        })?;
+
+        match recorded_hash {
+            Some(recorded_hash) if recorded_hash != &current_hash => {
+                return Err(MigrateError::ContentDrift {
+                    cql_file: cql_file.clone(),
+                    recorded_hash: recorded_hash.clone(),
+                    current_hash,
+                });
+            }
+            Some(_) => {}
+            None => {
+                queries::migrated::table::backfill_hash(
+                    session,
+                    &args.history_keyspace,
+                    &args.history_table,
+                    &cql_file.description(),
+                    &current_hash,
+                    args.consistency,
+                    &args.retry_policy,
+                )
+                .await?;
+            }
+        }
+    }
+
+    for cql_file in cql_files {
+        if applied_by_name.contains_key(&cql_file.description()) {
+            continue;
+        }
+
+        let statements = cql_file.statements().map_err(|source| MigrateError::ReadCqlFile {
+            path: cql_file.path.clone(),
+            source,
+            state: MigrateErrorState::after(newly_applied.last()),
+        })?;
+
+        for statement in &statements {
+            let query = configured_query(statement.clone(), args.consistency, &args.retry_policy);
+            session
+                .query(query, &[])
+                .await
+                .map_err(|err| MigrateError::ApplyCqlFile {
+                    cql_file: cql_file.clone(),
+                    source: anyhow::Error::from(err),
+                    state: MigrateErrorState::after(newly_applied.last()),
+                })?;
+        }
+
+        let content_hash = cql_file.content_hash().map_err(|source| MigrateError::ReadCqlFile {
+            path: cql_file.path.clone(),
+            source,
+            state: MigrateErrorState::after(newly_applied.last()),
+        })?;
+
+        queries::migrated::table::record(
+            session,
+            &args.history_keyspace,
+            &args.history_table,
+            &cql_file.description(),
+            &content_hash,
+            args.consistency,
+            &args.retry_policy,
+        )
+        .await
+        .map_err(|source| MigrateError::RecordHistoryWrite {
+            cql_file: cql_file.clone(),
+            source,
+            state: MigrateErrorState::after(newly_applied.last()),
+        })?;
+
+        await_schema_agreement(
+            session,
+            Some(cql_file.clone()),
+            args.schema_agreement_timeout,
+        )
+        .await?;
+
+        newly_applied.push(cql_file.clone());
+    }
+
+    Ok(newly_applied)
+}
+
+/// configured_query builds a [scylla::statement::query::Query] for `text` with `consistency` and
+/// `retry_policy` applied, for use against the applied keyspace or the history table.
+pub(crate) fn configured_query(
+    text: String,
+    consistency: ConsistencyLevel,
+    retry_policy: &Option<Arc<dyn RetryPolicy>>,
+) -> scylla::statement::query::Query {
+    let mut query = scylla::statement::query::Query::new(text);
+    query.set_consistency(consistency.into());
+    if let Some(retry_policy) = retry_policy {
+        query.set_retry_policy(Some(retry_policy.clone()));
+    }
+    query
+}
+
+/// await_schema_agreement polls the cluster until all known nodes agree on the current schema
+/// version, or returns [MigrateError::SchemaAgreementTimeout] once `timeout` elapses. `cql_file`
+/// identifies which migration step the agreement wait followed, for error reporting.
+pub(crate) async fn await_schema_agreement(
+    session: &Session,
+    cql_file: Option<CqlFile>,
+    timeout: Duration,
+) -> Result<(), MigrateError> {
+    tokio::time::timeout(timeout, session.await_schema_agreement())
+        .await
+        .map_err(|_| MigrateError::SchemaAgreementTimeout {
+            cql_file,
+            timeout_secs: timeout.as_secs(),
+        })?
+        .map_err(|err| MigrateError::Other {
+            source: anyhow::anyhow!(err),
+        })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+
+    #[tokio::test]
+    async fn test_await_schema_agreement_succeeds_with_ample_timeout() {
+        let session = test_utils::cql_session().await;
+        await_schema_agreement(&session, None, Duration::from_secs(10))
+            .await
+            .expect("schema agreement within timeout");
+    }
+
+    #[tokio::test]
+    async fn test_await_schema_agreement_times_out_with_zero_duration() {
+        let session = test_utils::cql_session().await;
+        let cql_file = CqlFile {
+            path: PathBuf::from("V1__create_widgets.cql"),
+        };
+
+        let err = await_schema_agreement(&session, Some(cql_file.clone()), Duration::from_secs(0))
+            .await
+            .expect_err("zero timeout should not reach schema agreement");
+        match err {
+            MigrateError::SchemaAgreementTimeout {
+                cql_file: timed_out_on,
+                timeout_secs,
+            } => {
+                assert_eq!(timed_out_on, Some(cql_file));
+                assert_eq!(timeout_secs, 0);
+            }
+            other => panic!("expected SchemaAgreementTimeout, got {other:?}"),
+        }
+    }
+
+    /// A history table whose `content_hash` column can't hold the string cquill writes to it
+    /// stands in for any history-write failure that happens after a file's statements already
+    /// applied: `perform` should report [MigrateError::RecordHistoryWrite] with a state that
+    /// doesn't yet include the file, since the history table was never actually updated.
+    #[tokio::test]
+    async fn test_perform_returns_record_history_write_state_when_history_write_fails() {
+        let session = test_utils::cql_session().await;
+        let keyspace = test_utils::create_keyspace(&session).await;
+        let history_table = String::from("migrated_cql");
+        session
+            .query(
+                format!(
+                    "CREATE TABLE {}.{} (cql_file text, applied_at timestamp, content_hash int, \
+                     PRIMARY KEY (cql_file))",
+                    keyspace.name, history_table
+                ),
+                &[],
+            )
+            .await
+            .expect("create mis-typed history table");
+
+        let cql_dir = test_utils::temp_cql_dir();
+        let cql_file = test_utils::write_cql_file(
+            &cql_dir,
+            "V1__create_widgets.cql",
+            "CREATE TABLE widgets (id int PRIMARY KEY);",
+        );
+
+        let err = perform(
+            &session,
+            &[cql_file.clone()],
+            MigrateArgs {
+                cql_dir: cql_dir.clone(),
+                apply_keyspace: keyspace.name.clone(),
+                history_keyspace: keyspace.name.clone(),
+                history_table: history_table.clone(),
+                schema_agreement_timeout: Duration::from_secs(10),
+                consistency: ConsistencyLevel::LocalQuorum,
+                retry_policy: None,
+            },
+        )
+        .await
+        .expect_err("mis-typed content_hash column should fail the record step");
+
+        match err {
+            MigrateError::RecordHistoryWrite {
+                cql_file: applied,
+                state,
+                ..
+            } => {
+                assert_eq!(applied, cql_file);
+                assert_eq!(state, MigrateErrorState::NoFilesApplied);
+            }
+            other => panic!("expected RecordHistoryWrite, got {other:?}"),
+        }
+
+        queries::keyspace::drop(&session, &keyspace.name)
+            .await
+            .expect("drop keyspace");
+    }
+
+    #[tokio::test]
+    async fn test_perform_detects_content_drift_and_aborts() {
+        let harness = test_utils::TestHarness::builder().initialize().await;
+        let apply_keyspace = test_utils::create_keyspace(&harness.session).await;
+        let cql_dir = test_utils::temp_cql_dir();
+        let cql_file = test_utils::write_cql_file(
+            &cql_dir,
+            "V1__create_widgets.cql",
+            "CREATE TABLE widgets (id int PRIMARY KEY);",
+        );
+
+        perform(
+            &harness.session,
+            &[cql_file.clone()],
+            MigrateArgs {
+                cql_dir: cql_dir.clone(),
+                apply_keyspace: apply_keyspace.name.clone(),
+                history_keyspace: harness.cquill_keyspace.clone(),
+                history_table: harness.cquill_table.clone(),
+                schema_agreement_timeout: Duration::from_secs(10),
+                consistency: ConsistencyLevel::LocalQuorum,
+                retry_policy: None,
+            },
+        )
+        .await
+        .expect("first run applies the file");
+
+        test_utils::write_cql_file(
+            &cql_dir,
+            "V1__create_widgets.cql",
+            "CREATE TABLE widgets (id int PRIMARY KEY, name text);",
+        );
+
+        let err = perform(
+            &harness.session,
+            &[cql_file.clone()],
+            MigrateArgs {
+                cql_dir: cql_dir.clone(),
+                apply_keyspace: apply_keyspace.name.clone(),
+                history_keyspace: harness.cquill_keyspace.clone(),
+                history_table: harness.cquill_table.clone(),
+                schema_agreement_timeout: Duration::from_secs(10),
+                consistency: ConsistencyLevel::LocalQuorum,
+                retry_policy: None,
+            },
+        )
+        .await
+        .expect_err("drifted file should abort instead of re-applying");
+
+        match err {
+            MigrateError::ContentDrift {
+                cql_file: drifted, ..
+            } => assert_eq!(drifted, cql_file),
+            other => panic!("expected ContentDrift, got {other:?}"),
+        }
+
+        queries::keyspace::drop(&harness.session, &apply_keyspace.name)
+            .await
+            .expect("drop apply keyspace");
+        harness.drop_keyspace().await;
+    }
+
+    #[tokio::test]
+    async fn test_perform_backfills_missing_content_hash() {
+        let harness = test_utils::TestHarness::builder().initialize().await;
+        let apply_keyspace = test_utils::create_keyspace(&harness.session).await;
+        let cql_dir = test_utils::temp_cql_dir();
+        let cql_file = test_utils::write_cql_file(
+            &cql_dir,
+            "V1__create_widgets.cql",
+            "CREATE TABLE widgets (id int PRIMARY KEY);",
+        );
+
+        // Seed a history row as it would have been written before the content_hash column
+        // existed, i.e. with no content_hash at all.
+        harness
+            .session
+            .query(
+                format!(
+                    "INSERT INTO {}.{} (cql_file, applied_at) VALUES (?, toTimestamp(now()))",
+                    harness.cquill_keyspace, harness.cquill_table
+                ),
+                (cql_file.description(),),
+            )
+            .await
+            .expect("seed pre-content-hash history row");
+
+        let newly_applied = perform(
+            &harness.session,
+            &[cql_file.clone()],
+            MigrateArgs {
+                cql_dir: cql_dir.clone(),
+                apply_keyspace: apply_keyspace.name.clone(),
+                history_keyspace: harness.cquill_keyspace.clone(),
+                history_table: harness.cquill_table.clone(),
+                schema_agreement_timeout: Duration::from_secs(10),
+                consistency: ConsistencyLevel::LocalQuorum,
+                retry_policy: None,
+            },
+        )
+        .await
+        .expect("backfills the missing hash instead of reapplying");
+        assert!(newly_applied.is_empty());
+
+        let applied = queries::migrated::table::applied_files(
+            &harness.session,
+            &harness.cquill_keyspace,
+            &harness.cquill_table,
+        )
+        .await
+        .expect("read history table");
+        let backfilled = applied
+            .into_iter()
+            .find(|applied_file| applied_file.cql_file == cql_file.description())
+            .expect("history row still present");
+        assert_eq!(
+            backfilled.content_hash,
+            Some(cql_file.content_hash().expect("content hash"))
+        );
+
+        queries::keyspace::drop(&harness.session, &apply_keyspace.name)
+            .await
+            .expect("drop apply keyspace");
+        harness.drop_keyspace().await;
+    }
+}
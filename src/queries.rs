@@ -0,0 +1,189 @@
+use anyhow::Result;
+use scylla::Session;
+
+/// table_names_from_session_metadata reads the table names of `keyspace_name` from the
+/// [Session]'s cached cluster metadata, returning an `Err` when the keyspace is not present in
+/// that metadata (i.e. it does not exist).
+pub fn table_names_from_session_metadata(
+    session: &Session,
+    keyspace_name: &str,
+) -> Result<Vec<String>> {
+    let cluster_data = session.get_cluster_data();
+    let keyspace = cluster_data
+        .get_keyspace_info()
+        .get(keyspace_name)
+        .ok_or_else(|| anyhow::anyhow!("keyspace {keyspace_name} not found"))?;
+    Ok(keyspace.tables.keys().cloned().collect())
+}
+
+pub mod keyspace {
+    use anyhow::Result;
+    use scylla::Session;
+
+    use crate::keyspace::{KeyspaceOpts, ReplicationFactor};
+
+    /// create issues a `CREATE KEYSPACE IF NOT EXISTS` statement for `keyspace`, translating its
+    /// [ReplicationFactor] into the corresponding CQL replication map.
+    pub async fn create(session: &Session, keyspace: &KeyspaceOpts) -> Result<()> {
+        let replication = match keyspace.replication.as_ref() {
+            Some(ReplicationFactor::SimpleStrategy { factor }) => format!(
+                "{{ 'class': 'SimpleStrategy', 'replication_factor': {factor} }}"
+            ),
+            Some(ReplicationFactor::NetworkTopologyStrategy { datacenter_factors }) => {
+                let mut entries: Vec<String> = datacenter_factors
+                    .iter()
+                    .map(|(dc, factor)| format!("'{dc}': {factor}"))
+                    .collect();
+                entries.sort();
+                format!(
+                    "{{ 'class': 'NetworkTopologyStrategy', {} }}",
+                    entries.join(", ")
+                )
+            }
+            None => crate::keyspace::REPLICATION.to_string(),
+        };
+        let statement = format!(
+            "CREATE KEYSPACE IF NOT EXISTS {} WITH replication = {}",
+            keyspace.name, replication
+        );
+        session.query(statement, &[]).await?;
+        Ok(())
+    }
+
+    /// drop issues a `DROP KEYSPACE IF EXISTS` statement, used by tests to clean up after
+    /// themselves.
+    pub async fn drop(session: &Session, keyspace_name: &str) -> Result<()> {
+        session
+            .query(format!("DROP KEYSPACE IF EXISTS {keyspace_name}"), &[])
+            .await?;
+        Ok(())
+    }
+}
+
+pub mod migrated {
+    pub mod table {
+        use anyhow::Result;
+        use scylla::Session;
+
+        /// AppliedFile is a single row already recorded in the `migrated_cql` history table.
+        /// `content_hash` is `None` for rows written before the content-hash column existed, and
+        /// should be backfilled via [backfill_hash] once the current on-disk hash is known.
+        pub struct AppliedFile {
+            pub cql_file: String,
+            pub content_hash: Option<String>,
+        }
+
+        /// create issues the `CREATE TABLE` statement for the `migrated_cql` history table within
+        /// `keyspace_name`, tracking which cql file paths have already been applied and a content
+        /// hash of each so drift from the applied file can be detected on later runs.
+        pub async fn create(
+            session: &Session,
+            keyspace_name: &str,
+            table_name: &str,
+        ) -> Result<()> {
+            let statement = format!(
+                "CREATE TABLE IF NOT EXISTS {keyspace_name}.{table_name} (\
+                 cql_file text, \
+                 applied_at timestamp, \
+                 content_hash text, \
+                 PRIMARY KEY (cql_file))"
+            );
+            session.query(statement, &[]).await?;
+            Ok(())
+        }
+
+        /// add_content_hash_column issues `ALTER TABLE ... ADD content_hash text` against a
+        /// `migrated_cql` history table created before that column existed, so [applied_files]'
+        /// `SELECT` of `content_hash` doesn't fail with "undefined column name" on upgrade. `ALTER
+        /// TABLE ... ADD` has no `IF NOT EXISTS` form, so the "already exists" error the driver
+        /// returns when the column is already present is swallowed instead.
+        pub async fn add_content_hash_column(
+            session: &Session,
+            keyspace_name: &str,
+            table_name: &str,
+        ) -> Result<()> {
+            let statement =
+                format!("ALTER TABLE {keyspace_name}.{table_name} ADD content_hash text");
+            match session.query(statement, &[]).await {
+                Ok(_) => Ok(()),
+                Err(err) if err.to_string().to_lowercase().contains("already exist") => Ok(()),
+                Err(err) => Err(anyhow::Error::from(err)),
+            }
+        }
+
+        /// applied_files returns the cql files already recorded in the history table, in no
+        /// particular order.
+        pub async fn applied_files(
+            session: &Session,
+            keyspace_name: &str,
+            table_name: &str,
+        ) -> Result<Vec<AppliedFile>> {
+            let rows = session
+                .query(
+                    format!("SELECT cql_file, content_hash FROM {keyspace_name}.{table_name}"),
+                    &[],
+                )
+                .await?
+                .rows
+                .unwrap_or_default();
+            rows.into_iter()
+                .map(|row| {
+                    row.into_typed::<(String, Option<String>)>()
+                        .map(|(cql_file, content_hash)| AppliedFile {
+                            cql_file,
+                            content_hash,
+                        })
+                        .map_err(anyhow::Error::from)
+                })
+                .collect()
+        }
+
+        /// record inserts a history row marking `cql_file` as applied with `content_hash`, at the
+        /// given [crate::migrate::ConsistencyLevel] and with the given retry policy, so a
+        /// transient coordinator hiccup doesn't leave the history table out of sync with applied
+        /// DDL.
+        pub async fn record(
+            session: &Session,
+            keyspace_name: &str,
+            table_name: &str,
+            cql_file: &str,
+            content_hash: &str,
+            consistency: crate::migrate::ConsistencyLevel,
+            retry_policy: &Option<std::sync::Arc<dyn scylla::retry_policy::RetryPolicy>>,
+        ) -> Result<()> {
+            let query = crate::migrate::configured_query(
+                format!(
+                    "INSERT INTO {keyspace_name}.{table_name} (cql_file, applied_at, content_hash) \
+                     VALUES (?, toTimestamp(now()), ?)"
+                ),
+                consistency,
+                retry_policy,
+            );
+            session.query(query, (cql_file, content_hash)).await?;
+            Ok(())
+        }
+
+        /// backfill_hash sets `content_hash` on a row written before that column existed, without
+        /// disturbing its recorded `applied_at`, at the given [crate::migrate::ConsistencyLevel]
+        /// and retry policy so it's consistent with how [record] writes the same table.
+        pub async fn backfill_hash(
+            session: &Session,
+            keyspace_name: &str,
+            table_name: &str,
+            cql_file: &str,
+            content_hash: &str,
+            consistency: crate::migrate::ConsistencyLevel,
+            retry_policy: &Option<std::sync::Arc<dyn scylla::retry_policy::RetryPolicy>>,
+        ) -> Result<()> {
+            let query = crate::migrate::configured_query(
+                format!(
+                    "UPDATE {keyspace_name}.{table_name} SET content_hash = ? WHERE cql_file = ?"
+                ),
+                consistency,
+                retry_policy,
+            );
+            session.query(query, (content_hash, cql_file)).await?;
+            Ok(())
+        }
+    }
+}
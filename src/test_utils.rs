@@ -0,0 +1,96 @@
+use std::path::{Path, PathBuf};
+
+use scylla::Session;
+
+use crate::cql::CqlFile;
+use crate::keyspace::KeyspaceOpts;
+use crate::queries;
+
+/// cql_session connects to the local cluster used by integration tests, per [CassandraOpts]'s
+/// `CASSANDRA_NODE`/default node address convention.
+pub(crate) async fn cql_session() -> Session {
+    crate::cql_session(&crate::CassandraOpts::default())
+        .await
+        .expect("test session connect")
+}
+
+/// keyspace_name generates a unique-enough keyspace name for a single test run so concurrent
+/// test runs don't collide.
+pub(crate) fn keyspace_name() -> String {
+    static NEXT: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+    let n = NEXT.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    format!("cquill_test_{}_{}", std::process::id(), n)
+}
+
+/// create_keyspace creates and returns a fresh [KeyspaceOpts] keyspace for a test to use.
+pub(crate) async fn create_keyspace(session: &Session) -> KeyspaceOpts {
+    let keyspace_opts = KeyspaceOpts::simple(keyspace_name(), 1);
+    queries::keyspace::create(session, &keyspace_opts)
+        .await
+        .expect("create test keyspace");
+    keyspace_opts
+}
+
+/// temp_cql_dir creates and returns a fresh, empty directory for a single test to write `.cql`
+/// files into, so [crate::migrate::perform] has real files on disk to read.
+pub(crate) fn temp_cql_dir() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+        "cquill_test_cql_{}_{}",
+        std::process::id(),
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .expect("system time")
+            .as_nanos()
+    ));
+    std::fs::create_dir_all(&dir).expect("create temp cql dir");
+    dir
+}
+
+/// write_cql_file writes `contents` to `file_name` within `dir`, returning the corresponding
+/// [CqlFile].
+pub(crate) fn write_cql_file(dir: &Path, file_name: &str, contents: &str) -> CqlFile {
+    let path = dir.join(file_name);
+    std::fs::write(&path, contents).expect("write cql file");
+    CqlFile { path }
+}
+
+/// TestHarness bundles a connected [Session] with an already-prepared cquill keyspace and
+/// history table, for tests that exercise behavior downstream of `prepare_cquill_keyspace`.
+pub(crate) struct TestHarness {
+    pub session: Session,
+    pub cquill_keyspace: String,
+    pub cquill_table: String,
+}
+
+impl TestHarness {
+    pub(crate) fn builder() -> TestHarnessBuilder {
+        TestHarnessBuilder
+    }
+
+    pub(crate) async fn drop_keyspace(&self) {
+        queries::keyspace::drop(&self.session, &self.cquill_keyspace)
+            .await
+            .expect("drop keyspace");
+    }
+}
+
+pub(crate) struct TestHarnessBuilder;
+
+impl TestHarnessBuilder {
+    pub(crate) async fn initialize(self) -> TestHarness {
+        let session = cql_session().await;
+        let cquill_keyspace = keyspace_name();
+        let cquill_table = String::from("migrated_cql");
+        queries::keyspace::create(&session, &KeyspaceOpts::simple(cquill_keyspace.clone(), 1))
+            .await
+            .expect("create cquill keyspace");
+        queries::migrated::table::create(&session, &cquill_keyspace, &cquill_table)
+            .await
+            .expect("create history table");
+        TestHarness {
+            session,
+            cquill_keyspace,
+            cquill_table,
+        }
+    }
+}